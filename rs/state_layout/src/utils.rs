@@ -3,6 +3,218 @@ use ic_sys::fs::copy_file_sparse;
 use std::io::Error;
 use std::path::Path;
 
+/// Copies the contents of `src` into `dst`, preferring the kernel-side
+/// `copy_file_range(2)` fast path on Linux and falling back to
+/// `copy_file_sparse` when that isn't available (e.g. `dst` is a FIFO or
+/// device node, or the kernel doesn't support it for this pair of files).
+fn copy_data(src: &Path, dst: &Path) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if copy_file_range_sparse(src, dst)? {
+            return Ok(());
+        }
+    }
+    copy_file_sparse(src, dst)
+}
+
+/// Copies `src` to `dst` using `copy_file_range(2)`, walking the source's
+/// data/hole extents with `lseek(SEEK_DATA)`/`lseek(SEEK_HOLE)` so holes stay
+/// sparse in `dst`. Returns `Ok(true)` if the copy was fully handled this way,
+/// or `Ok(false)` if `copy_file_range` isn't usable for this pair of files and
+/// the caller should fall back to `copy_file_sparse`.
+#[cfg(target_os = "linux")]
+fn copy_file_range_sparse(src: &Path, dst: &Path) -> std::io::Result<bool> {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // Opening a FIFO with the default blocking flags waits for a reader to
+    // show up on the other end before `open` even returns, which would hang
+    // the whole checkpoint pipeline if `dst` already exists as one. Check
+    // `dst`'s file type up front (if it exists at all) and route anything
+    // that isn't a regular file straight back to the `copy_file_sparse`
+    // fallback instead of relying on `copy_file_range` to fail later.
+    if let Ok(dst_metadata) = dst.symlink_metadata() {
+        if !dst_metadata.file_type().is_file() {
+            return Ok(false);
+        }
+    }
+
+    let src_file = File::open(src)?;
+    let dst_file = File::create(dst)?;
+    let len = src_file.metadata()?.len() as i64;
+
+    let src_fd = src_file.as_raw_fd();
+    let dst_fd = dst_file.as_raw_fd();
+
+    let mut pos: i64 = 0;
+    while pos < len {
+        let data_start = match unsafe { libc::lseek(src_fd, pos, libc::SEEK_DATA) } {
+            -1 => {
+                let err = Error::last_os_error();
+                return match err.raw_os_error() {
+                    // No more data after `pos`: the rest of the file is a hole.
+                    Some(libc::ENXIO) => break,
+                    Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EXDEV) => Ok(false),
+                    _ => Err(err),
+                };
+            }
+            off => off,
+        };
+        let hole_start = match unsafe { libc::lseek(src_fd, data_start, libc::SEEK_HOLE) } {
+            -1 => return Err(Error::last_os_error()),
+            off => off,
+        };
+
+        let mut off_in = data_start;
+        let mut off_out = data_start;
+        let mut remaining = (hole_start - data_start) as usize;
+        while remaining > 0 {
+            let copied = unsafe {
+                libc::copy_file_range(src_fd, &mut off_in, dst_fd, &mut off_out, remaining, 0)
+            };
+            match copied {
+                -1 => {
+                    let err = Error::last_os_error();
+                    return match err.raw_os_error() {
+                        // `EXDEV`: this codepath is also taken when `do_copy`
+                        // found src/dst on different filesystems, so cross-fs
+                        // copy_file_range not being supported here must fall
+                        // back, not propagate as an error.
+                        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EXDEV) => Ok(false),
+                        _ => Err(err),
+                    };
+                }
+                // `remaining` came from this same source file's SEEK_DATA/SEEK_HOLE
+                // bounds, so a `0` return here means the source shrank out from under
+                // us mid-copy, not a legitimate EOF. Treating it as "done" would
+                // silently leave the rest of this data span zero-filled in `dst`;
+                // fail loudly instead.
+                0 => {
+                    return Err(Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "copy_file_range returned 0 with {remaining} bytes remaining in {}; source may have been truncated concurrently",
+                            src.display()
+                        ),
+                    ))
+                }
+                n => remaining -= n as usize,
+            }
+        }
+        pos = hole_start;
+    }
+
+    dst_file.set_len(len as u64)?;
+    Ok(true)
+}
+
+/// On macOS, `ic_sys::fs::clone_file` has no APFS-specific handling and
+/// always reports `OperationNotSupported`, so every checkpoint copy falls
+/// back to a full byte copy. Try an APFS clone via `fclonefileat(2)` first,
+/// and if that isn't available (non-APFS volume), fall back to `copyfile(3)`
+/// with `COPYFILE_CLONE`, which still asks the kernel to clone when the
+/// destination volume supports it and otherwise does a plain copy.
+#[cfg(target_os = "macos")]
+fn clone_file(src: &Path, dst: &Path) -> Result<(), ic_sys::fs::FileCloneError> {
+    use ic_sys::fs::FileCloneError;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        // `fclonefileat(2)` takes an already-open fd for the source (not a
+        // dirfd+path pair, unlike `clonefileat(2)`).
+        fn fclonefileat(
+            srcfd: libc::c_int,
+            dst_dirfd: libc::c_int,
+            dst: *const libc::c_char,
+            flags: libc::c_int,
+        ) -> libc::c_int;
+
+        fn copyfile(
+            from: *const libc::c_char,
+            to: *const libc::c_char,
+            state: *mut libc::c_void,
+            flags: u32,
+        ) -> libc::c_int;
+    }
+
+    const COPYFILE_ACL: u32 = 1 << 0;
+    const COPYFILE_STAT: u32 = 1 << 1;
+    const COPYFILE_XATTR: u32 = 1 << 2;
+    const COPYFILE_DATA: u32 = 1 << 3;
+    const COPYFILE_CLONE: u32 = 1 << 24;
+    const COPYFILE_ALL: u32 = COPYFILE_ACL | COPYFILE_STAT | COPYFILE_XATTR | COPYFILE_DATA;
+
+    let to_err = |e: Error| FileCloneError::IoError(e);
+    let as_cstring = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| to_err(Error::new(std::io::ErrorKind::InvalidInput, e)))
+    };
+    let src_c = as_cstring(src)?;
+    let dst_c = as_cstring(dst)?;
+
+    let src_file = File::open(src).map_err(to_err)?;
+    let rc = unsafe { fclonefileat(src_file.as_raw_fd(), libc::AT_FDCWD, dst_c.as_ptr(), 0) };
+    if rc == 0 {
+        return Ok(());
+    }
+    let err = Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EXDEV) => return Err(FileCloneError::DifferentFileSystems),
+        Some(libc::ENOTSUP) | Some(libc::EOPNOTSUPP) => {
+            // Not an APFS volume: fall through and let copyfile try a
+            // volume-specific clone, or copy the bytes if it can't.
+        }
+        _ => return Err(to_err(err)),
+    }
+
+    let rc = unsafe {
+        copyfile(
+            src_c.as_ptr(),
+            dst_c.as_ptr(),
+            std::ptr::null_mut(),
+            COPYFILE_ALL | COPYFILE_CLONE,
+        )
+    };
+    if rc == 0 {
+        return Ok(());
+    }
+    let err = Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOTSUP) | Some(libc::EOPNOTSUPP) => Err(FileCloneError::OperationNotSupported),
+        _ => Err(to_err(err)),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn clone_file(src: &Path, dst: &Path) -> Result<(), ic_sys::fs::FileCloneError> {
+    ic_sys::fs::clone_file(src, dst)
+}
+
+/// Whether `clone_file` is known to succeed (`Reflink`) or fail between a
+/// given pair of filesystems, keyed by `(src_dev, dst_dev)`. `do_copy` previously tracked this as a pair
+/// of process-global atomics, which meant one cross-filesystem or
+/// non-reflink-capable copy would permanently disable reflinks for *every*
+/// subsequent copy, even between volumes that do support them. Keying by the
+/// device pair lets a multi-volume `state_root` keep using reflinks on the
+/// volumes that support them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Capability {
+    Reflink,
+    DifferentFileSystems,
+    OperationNotSupported,
+}
+
+fn capability_cache() -> &'static std::sync::Mutex<std::collections::HashMap<(u64, u64), Capability>>
+{
+    static CACHE: std::sync::OnceLock<
+        std::sync::Mutex<std::collections::HashMap<(u64, u64), Capability>>,
+    > = std::sync::OnceLock::new();
+    CACHE.get_or_init(|| std::sync::Mutex::new(std::collections::HashMap::new()))
+}
+
 /// Copies `src` into `dst`.
 ///
 /// Attempts to make a reflink (copy-on-write clone) of `src` into `dst` first.
@@ -10,10 +222,7 @@ use std::path::Path;
 /// regular file copy.
 pub fn do_copy(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io::Result<()> {
     use ic_sys::fs::FileCloneError;
-    use std::sync::atomic::{AtomicBool, Ordering};
-
-    static ON_COW_FS: AtomicBool = AtomicBool::new(true);
-    static SAME_FS: AtomicBool = AtomicBool::new(true);
+    use std::os::unix::fs::MetadataExt;
 
     let on_err = |e: Error| -> Error {
         Error::new(
@@ -27,10 +236,41 @@ pub fn do_copy(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io::Result<(
         )
     };
 
-    if ON_COW_FS.load(Ordering::Relaxed) && SAME_FS.load(Ordering::Relaxed) {
-        match ic_sys::fs::clone_file(src, dst) {
+    // `dst` doesn't exist yet, so key the cache off its parent directory's
+    // device rather than `dst` itself.
+    let dev_pair = match (
+        src.metadata(),
+        dst.parent().unwrap_or(Path::new(".")).metadata(),
+    ) {
+        (Ok(src_meta), Ok(dst_dir_meta)) => Some((src_meta.dev(), dst_dir_meta.dev())),
+        _ => None,
+    };
+    let cached = dev_pair.and_then(|pair| capability_cache().lock().unwrap().get(&pair).copied());
+
+    // Records `capability` for `dev_pair` and returns whether this is the
+    // first time this dev pair was found lacking, so callers only warn once.
+    let record = |capability: Capability| -> bool {
+        match dev_pair {
+            Some(pair) => capability_cache()
+                .lock()
+                .unwrap()
+                .insert(pair, capability)
+                != Some(capability),
+            None => true,
+        }
+    };
+
+    if !matches!(
+        cached,
+        Some(Capability::DifferentFileSystems) | Some(Capability::OperationNotSupported)
+    ) {
+        match clone_file(src, dst) {
+            Ok(()) => {
+                record(Capability::Reflink);
+                Ok(())
+            }
             Err(FileCloneError::DifferentFileSystems) => {
-                if SAME_FS.swap(false, Ordering::Relaxed) {
+                if record(Capability::DifferentFileSystems) {
                     warn!(
                         log,
                         "state_manager.state_root spans multiple filesystems \
@@ -39,11 +279,11 @@ pub fn do_copy(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io::Result<(
                         dst.display()
                     );
                 }
-                copy_file_sparse(src, dst).map_err(on_err)?;
+                copy_data(src, dst).map_err(on_err)?;
                 Ok(())
             }
             Err(FileCloneError::OperationNotSupported) => {
-                if ON_COW_FS.swap(false, Ordering::Relaxed) {
+                if record(Capability::OperationNotSupported) {
                     warn!(
                         log,
                         "StateManager runs on a filesystem not supporting reflinks \
@@ -52,7 +292,7 @@ pub fn do_copy(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io::Result<(
                         dst.display(),
                     );
                 }
-                copy_file_sparse(src, dst).map_err(on_err)?;
+                copy_data(src, dst).map_err(on_err)?;
                 Ok(())
             }
             Err(FileCloneError::IoError(e)) => Err(Error::new(
@@ -64,14 +304,86 @@ pub fn do_copy(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io::Result<(
                     e
                 ),
             )),
-            Ok(()) => Ok(()),
         }
     } else {
-        copy_file_sparse(src, dst).map_err(on_err)?;
+        copy_data(src, dst).map_err(on_err)?;
         Ok(())
     }
 }
 
+/// Options controlling metadata preservation in [`do_copy_with`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CopyOptions {
+    /// Replicate `src`'s mode bits, extended attributes (Linux only), and
+    /// mtime onto `dst`, and restore `dst`'s parent directory's mtime
+    /// afterward so that inserting a file into it doesn't bump the
+    /// directory's own mtime (which other subsystems may use for change
+    /// detection).
+    pub preserve_metadata: bool,
+}
+
+/// Like [`do_copy`], but additionally preserves source file metadata and the
+/// destination parent directory's mtime when `options.preserve_metadata` is
+/// set. `do_copy` itself keeps today's behavior unchanged.
+pub fn do_copy_with(
+    log: &ReplicaLogger,
+    src: &Path,
+    dst: &Path,
+    options: CopyOptions,
+) -> std::io::Result<()> {
+    let parent_metadata = if options.preserve_metadata {
+        dst.parent().map(|p| (p, p.metadata()))
+    } else {
+        None
+    };
+
+    do_copy(log, src, dst)?;
+
+    if options.preserve_metadata {
+        preserve_metadata(src, dst)?;
+        if let Some((parent, Ok(parent_metadata))) = parent_metadata {
+            filetime::set_file_mtime(
+                parent,
+                filetime::FileTime::from_last_modification_time(&parent_metadata),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replicates `src`'s mode bits, extended attributes (Linux only) and mtime
+/// onto `dst`.
+fn preserve_metadata(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let src_metadata = src.metadata()?;
+
+    // Set mode bits last: checkpoint files are commonly read-only (see
+    // `mark_readonly_and_hardlink_file`), and on Linux writing the `user.*`
+    // xattr namespace requires write permission on the inode, so replicating
+    // a read-only mode before `copy_xattrs` would make it fail with EACCES.
+    #[cfg(target_os = "linux")]
+    copy_xattrs(src, dst)?;
+
+    filetime::set_file_mtime(
+        dst,
+        filetime::FileTime::from_last_modification_time(&src_metadata),
+    )?;
+
+    std::fs::set_permissions(dst, src_metadata.permissions())?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(src: &Path, dst: &Path) -> std::io::Result<()> {
+    for name in xattr::list(src)? {
+        if let Some(value) = xattr::get(src, &name)? {
+            xattr::set(dst, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
 /// Copies `src` into `dst` using do_copy semantics overwriting destination if
 /// it exists
 pub fn do_copy_overwrite(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io::Result<()> {
@@ -81,6 +393,67 @@ pub fn do_copy_overwrite(log: &ReplicaLogger, src: &Path, dst: &Path) -> std::io
     do_copy(log, src, dst)
 }
 
+/// Recursively copies the directory tree rooted at `src_dir` into `dst_dir`,
+/// reflinking regular files with `do_copy`, recreating subdirectories, and
+/// recreating symlinks (without following them). Source files that are
+/// hardlinked to each other within the tree are hardlinked to each other in
+/// `dst_dir` too, instead of being reflinked/copied once per link. FIFOs,
+/// sockets and device nodes aren't meaningful to clone and are rejected.
+pub fn do_copy_dir(log: &ReplicaLogger, src_dir: &Path, dst_dir: &Path) -> std::io::Result<()> {
+    let mut inodes = std::collections::HashMap::new();
+    do_copy_dir_with_inodes(log, src_dir, dst_dir, &mut inodes)
+}
+
+fn do_copy_dir_with_inodes(
+    log: &ReplicaLogger,
+    src_dir: &Path,
+    dst_dir: &Path,
+    inodes: &mut std::collections::HashMap<(u64, u64), std::path::PathBuf>,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::create_dir_all(dst_dir)?;
+
+    for entry in std::fs::read_dir(src_dir)? {
+        let entry = entry?;
+        let src_path = entry.path();
+        let dst_path = dst_dir.join(entry.file_name());
+        // `DirEntry::metadata` doesn't follow symlinks, so this reflects
+        // `src_path` itself, not whatever it might point to.
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            do_copy_dir_with_inodes(log, &src_path, &dst_path, inodes)?;
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&src_path)?;
+            std::os::unix::fs::symlink(&target, &dst_path)?;
+        } else if file_type.is_file() {
+            let inode = (metadata.dev(), metadata.ino());
+            if metadata.nlink() > 1 {
+                if let Some(existing_dst) = inodes.get(&inode) {
+                    std::fs::hard_link(existing_dst, &dst_path)?;
+                    continue;
+                }
+            }
+            do_copy(log, &src_path, &dst_path)?;
+            if metadata.nlink() > 1 {
+                inodes.insert(inode, dst_path);
+            }
+        } else {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "do_copy_dir: refusing to copy special file {}",
+                    src_path.display()
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// Marks `src` as readonly and then hardlinks it to `dst` overwriting the destination if it exists.
 pub fn mark_readonly_and_hardlink_file(
     _log: &ReplicaLogger,
@@ -109,3 +482,68 @@ pub fn mark_readonly_and_hardlink_file(
     }
     std::fs::hard_link(src, dst)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_logger::replica_logger::no_op_logger;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn do_copy_preserves_sparse_holes() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let dst = dir.path().join("dst.bin");
+
+        // A 4 KiB data span, a 1 MiB hole, then another 4 KiB data span.
+        let mut f = std::fs::File::create(&src).unwrap();
+        f.write_all(&[0xab; 4096]).unwrap();
+        f.set_len(4096 + 1024 * 1024 + 4096).unwrap();
+        f.seek(SeekFrom::End(-4096)).unwrap();
+        f.write_all(&[0xcd; 4096]).unwrap();
+        drop(f);
+
+        do_copy(&no_op_logger(), &src, &dst).unwrap();
+
+        let mut dst_contents = Vec::new();
+        std::fs::File::open(&dst)
+            .unwrap()
+            .read_to_end(&mut dst_contents)
+            .unwrap();
+        assert_eq!(&dst_contents[..4096], &[0xab; 4096][..]);
+        assert!(dst_contents[4096..4096 + 1024 * 1024]
+            .iter()
+            .all(|&b| b == 0));
+        assert_eq!(&dst_contents[4096 + 1024 * 1024..], &[0xcd; 4096][..]);
+
+        // `dst` should actually be sparse, not just zero-filled: far fewer
+        // 512-byte blocks allocated than the logical length.
+        let dst_metadata = std::fs::metadata(&dst).unwrap();
+        assert!(dst_metadata.blocks() * 512 < dst_metadata.size());
+    }
+
+    #[test]
+    fn do_copy_dir_hardlinks_shared_inodes() {
+        let dir = tempdir().unwrap();
+        let src_dir = dir.path().join("src");
+        let dst_dir = dir.path().join("dst");
+        std::fs::create_dir(&src_dir).unwrap();
+
+        std::fs::write(src_dir.join("a"), b"shared contents").unwrap();
+        std::fs::hard_link(src_dir.join("a"), src_dir.join("b")).unwrap();
+
+        do_copy_dir(&no_op_logger(), &src_dir, &dst_dir).unwrap();
+
+        let dst_a_metadata = std::fs::metadata(dst_dir.join("a")).unwrap();
+        let dst_b_metadata = std::fs::metadata(dst_dir.join("b")).unwrap();
+        assert_eq!(dst_a_metadata.ino(), dst_b_metadata.ino());
+        assert_eq!(dst_a_metadata.nlink(), 2);
+        assert_eq!(
+            std::fs::read(dst_dir.join("b")).unwrap(),
+            b"shared contents"
+        );
+    }
+}